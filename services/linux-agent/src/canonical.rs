@@ -0,0 +1,172 @@
+// RFC 8785 JSON Canonicalization Scheme (JCS) serialization.
+// Used to produce deterministic bytes for `integrity.hash_sha256` so two
+// independent implementations (this agent, the ingest service, a future
+// second agent) compute the same hash for the same logical envelope
+// regardless of struct field order or `serde_json` re-serialization.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Serialize `value` through RFC 8785 JCS: object members sorted
+/// lexicographically by UTF-16 code unit, minimal string escaping, and
+/// numbers in their shortest round-trippable form. No insignificant
+/// whitespace is emitted.
+pub fn to_canonical_json<T: Serialize>(value: &T) -> Result<String> {
+    let value = serde_json::to_value(value).context("Failed to convert value to JSON")?;
+    let mut out = String::new();
+    write_value(&value, &mut out)?;
+    Ok(out)
+}
+
+fn write_value(value: &Value, out: &mut String) -> Result<()> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => out.push_str(&canonical_number(n)?),
+        Value::String(s) => out.push_str(&canonical_string(s)),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            // RFC 8785 orders members by UTF-16 code unit sequence, not by
+            // Rust's default byte-wise `str` ordering.
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort_by(|a, b| utf16_cmp(a, b));
+
+            out.push('{');
+            for (i, key) in keys.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&canonical_string(key));
+                out.push(':');
+                write_value(&map[*key], out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn utf16_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    a.encode_utf16().cmp(b.encode_utf16())
+}
+
+/// Minimal JSON string escaping (matches `serde_json`'s own escaping,
+/// which already satisfies JCS: only `"`, `\`, and control characters
+/// below U+0020 are escaped).
+fn canonical_string(s: &str) -> String {
+    serde_json::to_string(s).expect("string serialization is infallible")
+}
+
+/// Shortest round-trippable number per JCS: integers with no decimal
+/// point or leading zeros, floats via the ECMAScript `Number::toString`
+/// algorithm (no `+` on the exponent, no unnecessary trailing zeros).
+fn canonical_number(n: &serde_json::Number) -> Result<String> {
+    if let Some(i) = n.as_i64() {
+        return Ok(i.to_string());
+    }
+    if let Some(u) = n.as_u64() {
+        return Ok(u.to_string());
+    }
+    let f = n
+        .as_f64()
+        .context("Number is neither an integer nor representable as f64")?;
+    if !f.is_finite() {
+        anyhow::bail!("Cannot canonicalize non-finite number: {}", f);
+    }
+    Ok(format_shortest_f64(f))
+}
+
+/// Format an f64 the way ECMAScript's `ToString` would: shortest decimal
+/// that round-trips, no `+` sign on the exponent, and `-0.0` canonicalizes
+/// to `"0"` (JCS mandates a single representation for zero).
+///
+/// Rust's `Display` for `f64` never emits exponential notation on its own
+/// (e.g. `1e21` prints as `"1000000000000000000000"`), so ECMA-262's
+/// switch to exponential form for magnitude >= 1e21 or < 1e-6 has to be
+/// applied explicitly here.
+fn format_shortest_f64(f: f64) -> String {
+    if f == 0.0 {
+        return "0".to_string();
+    }
+
+    let magnitude = f.abs();
+    if !(1e-6..1e21).contains(&magnitude) {
+        let exponential = format!("{:e}", f);
+        let idx = exponential.find('e').expect("{:e} always emits an exponent");
+        let (mantissa, exponent) = exponential.split_at(idx);
+        let exponent = exponent.trim_start_matches('e').trim_start_matches('+');
+        format!("{}e{}", mantissa, exponent)
+    } else {
+        format!("{}", f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reordered_keys_produce_identical_canonical_json() {
+        let a = json!({"b": 1, "a": 2, "c": {"y": 1, "x": 2}});
+        let b = json!({"c": {"x": 2, "y": 1}, "a": 2, "b": 1});
+        assert_eq!(to_canonical_json(&a).unwrap(), to_canonical_json(&b).unwrap());
+        assert_eq!(to_canonical_json(&a).unwrap(), r#"{"a":2,"b":1,"c":{"x":2,"y":1}}"#);
+    }
+
+    #[test]
+    fn utf16_cmp_orders_by_code_unit_not_byte() {
+        // "\u{10000}" encodes to a UTF-16 surrogate pair starting at 0xD800,
+        // which sorts *before* the single code unit 0xFFFF - the opposite
+        // of byte-wise `str` comparison, where "\u{10000}" (a 4-byte UTF-8
+        // sequence) sorts after "\u{FFFF}" (3 bytes).
+        assert_eq!(utf16_cmp("\u{FFFF}", "\u{10000}"), std::cmp::Ordering::Greater);
+        assert_eq!(utf16_cmp("a", "b"), std::cmp::Ordering::Less);
+        assert_eq!(utf16_cmp("same", "same"), std::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn canonical_number_round_trips_integers() {
+        assert_eq!(canonical_number(&serde_json::Number::from(0)).unwrap(), "0");
+        assert_eq!(canonical_number(&serde_json::Number::from(-42)).unwrap(), "-42");
+        assert_eq!(canonical_number(&serde_json::Number::from(u64::MAX)).unwrap(), u64::MAX.to_string());
+    }
+
+    #[test]
+    fn negative_zero_canonicalizes_to_zero() {
+        assert_eq!(format_shortest_f64(-0.0), "0");
+        assert_eq!(format_shortest_f64(0.0), "0");
+    }
+
+    #[test]
+    fn large_and_small_magnitudes_use_exponential_form() {
+        assert_eq!(format_shortest_f64(1e21), "1e21");
+        assert_eq!(format_shortest_f64(1.5e21), "1.5e21");
+        assert_eq!(format_shortest_f64(1e-7), "1e-7");
+        assert_eq!(format_shortest_f64(1.23e-10), "1.23e-10");
+    }
+
+    #[test]
+    fn boundary_magnitudes_stay_in_decimal_form() {
+        // Just inside the ECMA-262 thresholds (exponent 20 and -6), these
+        // must NOT switch to exponential notation.
+        assert_eq!(format_shortest_f64(1e20), "100000000000000000000");
+        assert_eq!(format_shortest_f64(1e-6), "0.000001");
+    }
+
+    #[test]
+    fn ordinary_floats_format_without_exponent() {
+        assert_eq!(format_shortest_f64(123.456), "123.456");
+        assert_eq!(format_shortest_f64(-1.5), "-1.5");
+    }
+}
@@ -0,0 +1,349 @@
+// Persistent append-only integrity chain state.
+//
+// Each invocation of the agent emits one event (or, in streaming mode, a
+// continuous sequence of them), and the events form a tamper-evident
+// chain: every envelope's `sequence`/`prev_hash_sha256` must follow on
+// from the last one this agent got accepted by the ingest service or
+// spooled for later delivery. That continuation point is tracked on disk so it
+// survives process restarts (it only resets when the machine itself
+// reboots, detected via `boot_id`).
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{verify_hash, EventEnvelope};
+
+#[cfg(test)]
+use crate::{compute_hash, EventIdentity, EventIntegrity};
+
+/// The chain continuation point: what `sequence`/`prev_hash_sha256` the
+/// *next* envelope constructed by this agent must use.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChainState {
+    /// boot_id this state was last advanced under; a differing boot_id
+    /// on load means the machine rebooted and the chain starts over.
+    pub boot_id: String,
+    pub next_sequence: u64,
+    pub prev_hash_sha256: Option<String>,
+    /// `ingested_at` recorded when the chain last advanced: the
+    /// server-assigned value from the acknowledgment of an accepted event,
+    /// or the envelope's `observed_at` when the event was spooled instead.
+    /// Kept for operational visibility into the chain.
+    pub last_ingested_at: Option<String>,
+}
+
+impl ChainState {
+    fn fresh(boot_id: &str) -> Self {
+        ChainState {
+            boot_id: boot_id.to_string(),
+            next_sequence: 0,
+            prev_hash_sha256: None,
+            last_ingested_at: None,
+        }
+    }
+
+    /// State after an envelope using this state's continuation point has
+    /// been committed (accepted by the ingest service, or spooled for later
+    /// delivery), recording `ingested_at` as `last_ingested_at`.
+    pub fn advance(&self, committed_hash_sha256: String, ingested_at: String) -> ChainState {
+        ChainState {
+            boot_id: self.boot_id.clone(),
+            next_sequence: self.next_sequence + 1,
+            prev_hash_sha256: Some(committed_hash_sha256),
+            last_ingested_at: Some(ingested_at),
+        }
+    }
+}
+
+/// Default path to the chain state file, rooted at `RANSOMEYE_STATE_DIR`
+/// (defaulting to `/var/lib/ransomeye` if unset).
+pub fn default_state_path() -> PathBuf {
+    let dir = std::env::var("RANSOMEYE_STATE_DIR").unwrap_or_else(|_| {
+        eprintln!("INFO: RANSOMEYE_STATE_DIR not set, using default: /var/lib/ransomeye");
+        "/var/lib/ransomeye".to_string()
+    });
+    Path::new(&dir).join("chain.json")
+}
+
+/// Load the chain state for `boot_id`, resetting to a fresh chain if no
+/// state file exists yet or if the stored state belongs to a previous
+/// boot (the machine rebooted since the chain last advanced).
+pub fn load_or_init(path: &Path, boot_id: &str) -> Result<ChainState> {
+    let state = match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str::<ChainState>(&contents)
+            .with_context(|| format!("Failed to parse chain state file: {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("INFO: No chain state file at {}, starting fresh chain", path.display());
+            return Ok(ChainState::fresh(boot_id));
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read chain state file: {}", path.display()))
+        }
+    };
+
+    if state.boot_id != boot_id {
+        eprintln!(
+            "INFO: boot_id changed ({} -> {}), resetting integrity chain",
+            state.boot_id, boot_id
+        );
+        return Ok(ChainState::fresh(boot_id));
+    }
+
+    Ok(state)
+}
+
+/// Persist `state` to `path`. Only called once the envelope that
+/// produced this state has been accepted by the ingest service or written
+/// to the spool, so a failed transmission never creates a gap in the chain.
+pub fn persist(path: &Path, state: &ChainState) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create state directory: {}", parent.display()))?;
+    }
+    let json = serde_json::to_string_pretty(state).context("Failed to serialize chain state")?;
+    fs::write(path, json).with_context(|| format!("Failed to write chain state file: {}", path.display()))
+}
+
+/// Walk a newline-delimited JSON file of previously stored envelopes and
+/// confirm the chain is intact: each envelope's own `hash_sha256` is
+/// consistent with its contents, consecutive envelopes within a boot
+/// segment have contiguous `sequence` numbers, and each envelope's
+/// `prev_hash_sha256` matches the recomputed hash of the envelope before
+/// it. A `prev_hash_sha256` of `None` starts a new boot segment and is
+/// only valid when paired with `sequence == 0`.
+pub fn verify_chain_file(path: &Path) -> Result<()> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read envelope file: {}", path.display()))?;
+
+    let mut previous: Option<(EventEnvelope, String)> = None;
+
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let envelope: EventEnvelope = serde_json::from_str(line)
+            .with_context(|| format!("Line {}: failed to parse event envelope", line_no + 1))?;
+
+        if !verify_hash(&envelope).with_context(|| format!("Line {}: failed to verify hash", line_no + 1))? {
+            anyhow::bail!(
+                "Line {}: stored hash_sha256 {} does not match recomputed hash (event_id {})",
+                line_no + 1,
+                envelope.integrity.hash_sha256,
+                envelope.event_id
+            );
+        }
+        let recomputed = envelope.integrity.hash_sha256.clone();
+
+        match (&previous, &envelope.integrity.prev_hash_sha256) {
+            (None, None) => {
+                if envelope.sequence != 0 {
+                    anyhow::bail!(
+                        "Line {}: first event in chain has sequence {} but prev_hash_sha256 is null (expected sequence 0)",
+                        line_no + 1,
+                        envelope.sequence
+                    );
+                }
+            }
+            (Some(_), None) => {
+                if envelope.sequence != 0 {
+                    anyhow::bail!(
+                        "Line {}: new boot segment has sequence {} but prev_hash_sha256 is null (expected sequence 0)",
+                        line_no + 1,
+                        envelope.sequence
+                    );
+                }
+            }
+            (Some((prev_envelope, prev_hash)), Some(claimed_prev_hash)) => {
+                if claimed_prev_hash != prev_hash {
+                    anyhow::bail!(
+                        "Line {}: prev_hash_sha256 {} does not match prior event's hash {} (event_id {})",
+                        line_no + 1,
+                        claimed_prev_hash,
+                        prev_hash,
+                        envelope.event_id
+                    );
+                }
+                if envelope.sequence != prev_envelope.sequence + 1 {
+                    anyhow::bail!(
+                        "Line {}: sequence {} is not contiguous with prior sequence {} (event_id {})",
+                        line_no + 1,
+                        envelope.sequence,
+                        prev_envelope.sequence,
+                        envelope.event_id
+                    );
+                }
+            }
+            (None, Some(claimed_prev_hash)) => {
+                anyhow::bail!(
+                    "Line {}: first event in file claims prev_hash_sha256 {} but there is no prior event",
+                    line_no + 1,
+                    claimed_prev_hash
+                );
+            }
+        }
+
+        previous = Some((envelope, recomputed));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn envelope(sequence: u64, prev_hash_sha256: Option<String>) -> EventEnvelope {
+        let mut envelope = EventEnvelope {
+            event_id: format!("event-{}", sequence),
+            machine_id: "test-machine".to_string(),
+            component: "linux_agent".to_string(),
+            component_instance_id: "test-instance".to_string(),
+            observed_at: "2026-01-01T00:00:00Z".to_string(),
+            ingested_at: "2026-01-01T00:00:00Z".to_string(),
+            sequence,
+            payload: serde_json::json!({"k": "v"}),
+            identity: EventIdentity {
+                hostname: "test-host".to_string(),
+                boot_id: "11111111-1111-1111-1111-111111111111".to_string(),
+                agent_version: "1.0.0".to_string(),
+            },
+            integrity: EventIntegrity {
+                hash_sha256: String::new(),
+                prev_hash_sha256,
+            },
+        };
+        envelope.integrity.hash_sha256 = compute_hash(&envelope).unwrap();
+        envelope
+    }
+
+    #[test]
+    fn advance_increments_sequence_and_carries_prev_hash() {
+        let state = ChainState::fresh("boot-a");
+        let next = state.advance("hash-0".to_string(), "2026-01-01T00:00:00Z".to_string());
+        assert_eq!(next.boot_id, "boot-a");
+        assert_eq!(next.next_sequence, 1);
+        assert_eq!(next.prev_hash_sha256, Some("hash-0".to_string()));
+        assert_eq!(next.last_ingested_at, Some("2026-01-01T00:00:00Z".to_string()));
+    }
+
+    #[test]
+    fn load_or_init_starts_fresh_when_no_file_exists() {
+        let dir = std::env::temp_dir().join(format!("chain-test-missing-{}", std::process::id()));
+        let path = dir.join("chain.json");
+        let state = load_or_init(&path, "boot-a").unwrap();
+        assert_eq!(state, ChainState::fresh("boot-a"));
+    }
+
+    #[test]
+    fn load_or_init_resets_on_boot_id_change() {
+        let dir = std::env::temp_dir().join(format!("chain-test-reset-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chain.json");
+        let advanced = ChainState::fresh("boot-a").advance("hash-0".to_string(), "2026-01-01T00:00:00Z".to_string());
+        persist(&path, &advanced).unwrap();
+
+        // Same boot_id: state carries forward unchanged.
+        let reloaded = load_or_init(&path, "boot-a").unwrap();
+        assert_eq!(reloaded, advanced);
+
+        // Different boot_id: the chain resets to a fresh state.
+        let reset = load_or_init(&path, "boot-b").unwrap();
+        assert_eq!(reset, ChainState::fresh("boot-b"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn verify_chain_file_accepts_contiguous_single_segment() {
+        let dir = std::env::temp_dir().join(format!("chain-test-verify-ok-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.ndjson");
+
+        let first = envelope(0, None);
+        let second = envelope(1, Some(first.integrity.hash_sha256.clone()));
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&first).unwrap()).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&second).unwrap()).unwrap();
+
+        assert!(verify_chain_file(&path).is_ok());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn verify_chain_file_accepts_new_boot_segment_resetting_to_sequence_zero() {
+        let dir = std::env::temp_dir().join(format!("chain-test-verify-segment-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.ndjson");
+
+        let boot_a = envelope(0, None);
+        // A new boot segment: prev_hash_sha256 is None again, sequence resets to 0.
+        let boot_b = envelope(0, None);
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&boot_a).unwrap()).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&boot_b).unwrap()).unwrap();
+
+        assert!(verify_chain_file(&path).is_ok());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn verify_chain_file_rejects_non_contiguous_sequence() {
+        let dir = std::env::temp_dir().join(format!("chain-test-verify-gap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.ndjson");
+
+        let first = envelope(0, None);
+        // Skips sequence 1 entirely.
+        let second = envelope(2, Some(first.integrity.hash_sha256.clone()));
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&first).unwrap()).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&second).unwrap()).unwrap();
+
+        let err = verify_chain_file(&path).unwrap_err();
+        assert!(err.to_string().contains("not contiguous"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn verify_chain_file_rejects_prev_hash_mismatch() {
+        let dir = std::env::temp_dir().join(format!("chain-test-verify-mismatch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.ndjson");
+
+        let first = envelope(0, None);
+        let second = envelope(1, Some("0".repeat(64)));
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&first).unwrap()).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&second).unwrap()).unwrap();
+
+        let err = verify_chain_file(&path).unwrap_err();
+        assert!(err.to_string().contains("does not match prior event's hash"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn verify_chain_file_rejects_tampered_hash() {
+        let dir = std::env::temp_dir().join(format!("chain-test-verify-tamper-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.ndjson");
+
+        let mut tampered = envelope(0, None);
+        tampered.payload = serde_json::json!({"k": "tampered after hashing"});
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&tampered).unwrap()).unwrap();
+
+        let err = verify_chain_file(&path).unwrap_err();
+        assert!(err.to_string().contains("does not match recomputed hash"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
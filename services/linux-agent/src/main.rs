@@ -4,7 +4,7 @@
 
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
-use reqwest::blocking::Client;
+use futures_util::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::clone::Clone;
@@ -12,6 +12,11 @@ use std::env;
 use std::process;
 use uuid::Uuid;
 
+mod canonical;
+mod chain;
+mod spool;
+mod transport;
+
 /// Exit codes for Linux Agent (Phase 10 requirement: Clear exit codes)
 #[repr(i32)]
 enum ExitCode {
@@ -20,6 +25,14 @@ enum ExitCode {
     StartupError = 2,
     RuntimeError = 3,
     FatalError = 4,
+    /// Ingest service acknowledged the event but echoed back a hash that
+    /// does not match what was sent - a silently-corrupting proxy/bug,
+    /// distinct from an ordinary transport failure.
+    IntegrityError = 5,
+    /// One or more envelopes are spooled on disk awaiting delivery (the
+    /// ingest service was unreachable). Not a failure: the chain is intact
+    /// and will be flushed on a subsequent run.
+    SpoolPending = 6,
 }
 
 /// Canonical event envelope structure matching event-envelope.schema.json exactly
@@ -51,7 +64,10 @@ struct EventEnvelope {
 
     // Required field: RFC3339 UTC timestamp (event-envelope.schema.json)
     // Contract compliance: ingested_at MUST be RFC3339 UTC (time-semantics.md)
-    // NOTE: For Phase 4, we set this to observed_at (ingest service will update it)
+    // NOTE: the ingest service has not seen this envelope yet when it is
+    // constructed, so this is seeded with observed_at as a placeholder; the
+    // server-assigned value comes back in the IngestAck and is recorded into
+    // the persisted chain state (`ChainState::last_ingested_at`), not here.
     #[serde(rename = "ingested_at")]
     ingested_at: String,
 
@@ -148,19 +164,25 @@ fn get_boot_id() -> Result<String> {
     Ok(boot_id)
 }
 
-/// Compute SHA256 hash of JSON-serialized event envelope (hardened)
+/// Compute SHA256 hash of the RFC 8785 canonical JSON form of the event envelope
 /// Phase 10 requirement: Hash computation must exclude hash_sha256 field itself
 /// Contract compliance: hash_sha256 MUST be 64-character hex string (event-envelope.schema.json)
+///
+/// The envelope is canonicalized (sorted object keys, minimal whitespace,
+/// shortest-round-trip numbers) before hashing so that any conforming
+/// re-serialization of the same logical envelope - by the ingest service,
+/// or by a second agent implementation - produces the identical hash,
+/// which is required for cross-verification of the integrity chain.
 fn compute_hash(envelope: &EventEnvelope) -> Result<String> {
     // Phase 10 requirement: Hash computation must exclude hash_sha256 field (contract compliance)
     // Create a copy of envelope with empty hash_sha256 for hashing
     let mut envelope_for_hash = envelope.clone();
     envelope_for_hash.integrity.hash_sha256 = String::new();
-    
-    // Serialize to canonical JSON (compact, sorted keys for deterministic hashing)
+
+    // Serialize to canonical JSON (RFC 8785 JCS) for deterministic hashing
     // Contract compliance: hash MUST be computed after all fields are populated
-    let json = serde_json::to_string(&envelope_for_hash)
-        .context("Failed to serialize event envelope to JSON")?;
+    let json = canonical::to_canonical_json(&envelope_for_hash)
+        .context("Failed to canonicalize event envelope to JSON")?;
 
     // Phase 10 requirement: Compute SHA256 hash with proper error handling
     let mut hasher = Sha256::new();
@@ -169,18 +191,30 @@ fn compute_hash(envelope: &EventEnvelope) -> Result<String> {
 
     // Convert to 64-character hex string
     let hash_str = format!("{:x}", hash);
-    
+
     // Phase 10 requirement: Verify hash format (64 hex chars)
     if hash_str.len() != 64 {
         anyhow::bail!("Computed hash is not 64 characters: {}", hash_str.len());
     }
-    
+
     Ok(hash_str)
 }
 
+/// Recompute `envelope`'s hash over its canonical JSON form and compare it
+/// against the stored `integrity.hash_sha256`. Returns `Ok(true)` when the
+/// envelope has not been tampered with since it was hashed. Used by
+/// `chain::verify_chain_file` to check each stored envelope in turn.
+fn verify_hash(envelope: &EventEnvelope) -> Result<bool> {
+    let recomputed = compute_hash(envelope).context("Failed to recompute hash for verification")?;
+    Ok(recomputed == envelope.integrity.hash_sha256)
+}
+
 /// Construct canonical event envelope
 /// Contract compliance: All fields MUST match event-envelope.schema.json exactly
-fn construct_event_envelope() -> Result<EventEnvelope> {
+///
+/// `chain_state` supplies the `sequence`/`prev_hash_sha256` this envelope
+/// must continue the integrity chain with (see `chain` module).
+fn construct_event_envelope(chain_state: &chain::ChainState) -> Result<EventEnvelope> {
     // Phase 10 requirement: Read required environment variables with clear error messages
     let component_instance_id = read_env_var(
         "RANSOMEYE_COMPONENT_INSTANCE_ID",
@@ -206,10 +240,10 @@ fn construct_event_envelope() -> Result<EventEnvelope> {
     let now_utc: DateTime<Utc> = Utc::now();
     let observed_at = now_utc.to_rfc3339();
 
-    // Phase 4: First event (sequence=0 for first event per schema constraint)
     // Contract compliance: sequence MUST be uint64 (0 to 2^64-1)
-    // Schema constraint: sequence=0 AND prev_hash_sha256 IS NULL (first event)
-    let sequence = 0u64;
+    // sequence=0 AND prev_hash_sha256 IS NULL only for the first event of a boot
+    // (see `chain` module); otherwise these continue the persisted chain.
+    let sequence = chain_state.next_sequence;
 
     // Phase 4 explicitly allows one dummy key/value in payload
     let payload = serde_json::json!({
@@ -235,7 +269,7 @@ fn construct_event_envelope() -> Result<EventEnvelope> {
         },
         integrity: EventIntegrity {
             hash_sha256: String::new(), // Empty placeholder for hash computation
-            prev_hash_sha256: None, // Contract compliance: sequence=0 means prev_hash_sha256=NULL (first event)
+            prev_hash_sha256: chain_state.prev_hash_sha256.clone(),
         },
     };
 
@@ -249,51 +283,51 @@ fn construct_event_envelope() -> Result<EventEnvelope> {
     Ok(envelope)
 }
 
-/// Transmit event to ingest service via HTTP (hardened)
-/// Phase 10 requirement: Proper error handling, clear error messages, timeout handling
-/// Contract compliance: No retries, no batching, no buffering (Phase 4 requirements)
-fn transmit_event(client: &Client, envelope: &EventEnvelope, ingest_url: &str) -> Result<()> {
-    // Phase 10 requirement: Explicit error handling for network operations
-    eprintln!("INFO: Transmitting event to ingest service: {}", ingest_url);
-    
-    // Contract compliance: No retries, no buffering, no background threads (Phase 4 requirements)
-    // Single HTTP POST request, fail if it fails
-    let response = match client
-        .post(ingest_url)
-        .json(envelope)
-        .send()
-    {
-        Ok(r) => r,
+/// `--verify <path>` mode: walk a newline-delimited JSON file of
+/// previously stored envelopes and confirm the integrity chain within it
+/// is unbroken, without transmitting anything.
+fn run_verify_mode(path: &str) {
+    eprintln!("STARTUP: Linux Agent starting in --verify mode: {}", path);
+    match chain::verify_chain_file(std::path::Path::new(path)) {
+        Ok(()) => {
+            eprintln!("INFO: Integrity chain verified OK: {}", path);
+            process::exit(ExitCode::Success as i32);
+        }
         Err(e) => {
-            eprintln!("ERROR: Failed to send HTTP request to ingest service: {}", e);
-            eprintln!("  Ingest URL: {}", ingest_url);
-            eprintln!("  Event ID: {}", envelope.event_id);
-            anyhow::bail!("HTTP request failed: {}", e);
+            eprintln!("FATAL: Integrity chain verification failed: {}", e);
+            eprintln!("  Error chain: {:?}", e);
+            process::exit(ExitCode::RuntimeError as i32);
         }
-    };
+    }
+}
 
-    // Phase 10 requirement: Check response status with clear error messages
-    if !response.status().is_success() {
-        let status = response.status();
-        let body = response.text().unwrap_or_else(|_| String::from("(no response body)"));
-        eprintln!("ERROR: Ingest service returned error status: {}", status);
-        eprintln!("  Response body: {}", body);
-        eprintln!("  Event ID: {}", envelope.event_id);
-        anyhow::bail!(
-            "Ingest service returned error status {}: {}",
-            status,
-            body
-        );
+/// Entry point: parses `--verify` synchronously (no network involved),
+/// otherwise enters a tokio runtime from `main` (the execution-layer
+/// crates use `block_on`/a task executor for exactly this) to drive the
+/// async transport and batched spool drain.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if let [_, flag, path] = args.as_slice() {
+        if flag == "--verify" {
+            run_verify_mode(path);
+            return;
+        }
     }
 
-    eprintln!("INFO: Event transmission successful: {}", envelope.event_id);
-    Ok(())
+    let rt = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("FATAL: Failed to build tokio runtime: {}", e);
+            process::exit(ExitCode::StartupError as i32);
+        }
+    };
+    rt.block_on(run());
 }
 
-fn main() {
+async fn run() {
     // Phase 10 requirement: Deterministic startup with proper error handling
     eprintln!("STARTUP: Linux Agent starting");
-    
+
     // Contract compliance: Read environment variables (env.contract.json)
     // Phase 10 requirement: Fail-fast on missing required variables
     let ingest_url = env::var("RANSOMEYE_INGEST_URL")
@@ -302,41 +336,263 @@ fn main() {
             "http://localhost:8000/events".to_string()
         });
 
-    // Phase 10 requirement: Construct event envelope with proper error handling
-    let envelope = match construct_event_envelope() {
-        Ok(env) => env,
+    // Phase 10 requirement: Proper error handling for chain state / boot_id lookup
+    let boot_id = match get_boot_id() {
+        Ok(id) => id,
         Err(e) => {
-            eprintln!("FATAL: Failed to construct event envelope: {}", e);
-            eprintln!("  Error chain: {}", format!("{:?}", e));
+            eprintln!("FATAL: Failed to get boot_id for integrity chain: {}", e);
             process::exit(ExitCode::StartupError as i32);
         }
     };
 
-    // Phase 10 requirement: Create HTTP client with error handling
-    let client = match Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()
-    {
-        Ok(c) => c,
+    let state_path = chain::default_state_path();
+    let mut chain_state = match chain::load_or_init(&state_path, &boot_id) {
+        Ok(s) => s,
         Err(e) => {
-            eprintln!("FATAL: Failed to create HTTP client: {}", e);
+            eprintln!("FATAL: Failed to load integrity chain state: {}", e);
+            eprintln!("  Error chain: {:?}", e);
+            process::exit(ExitCode::StartupError as i32);
+        }
+    };
+
+    // RANSOMEYE_TRANSPORT selects the wire protocol; "http" (default) sends
+    // exactly one event and exits, "websocket" opens a long-lived connection
+    // and streams a continuous chained sequence of events.
+    let transport_mode = env::var("RANSOMEYE_TRANSPORT").unwrap_or_else(|_| "http".to_string());
+    let transport: Box<dyn transport::Transport> = match transport_mode.as_str() {
+        "http" => match transport::HttpTransport::new(ingest_url.clone()) {
+            Ok(t) => Box::new(t),
+            Err(e) => {
+                eprintln!("FATAL: Failed to create HTTP transport: {}", e);
+                process::exit(ExitCode::StartupError as i32);
+            }
+        },
+        "websocket" => match transport::WebSocketTransport::connect(&ingest_url).await {
+            Ok(t) => Box::new(t),
+            Err(e) => {
+                eprintln!("FATAL: Failed to create WebSocket transport: {}", e);
+                eprintln!("  Error chain: {:?}", e);
+                process::exit(ExitCode::StartupError as i32);
+            }
+        },
+        other => {
+            eprintln!("FATAL: Unknown RANSOMEYE_TRANSPORT: {} (expected \"http\" or \"websocket\")", other);
+            process::exit(ExitCode::ConfigError as i32);
+        }
+    };
+
+    // Offline resilience: drain anything left over from a previous run
+    // over the transport just created, before emitting a new event over
+    // HTTP or starting the long-lived stream, so delivery order is
+    // preserved and no spooled entry is ever permanently stranded.
+    let spool_dir = spool::default_spool_dir();
+    let spool_config = spool::SpoolConfig::from_env();
+    let max_retries: u32 = env::var("RANSOMEYE_MAX_RETRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3);
+    // Bounds how many spooled (or streamed) envelopes may be in flight to
+    // the ingest service concurrently. Spooled envelopes already carry their
+    // chain position, so draining them does not touch the persisted chain
+    // state (see `spool::drain`).
+    let inflight: usize = env::var("RANSOMEYE_INFLIGHT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(4);
+
+    let drain_outcome = match spool::drain(&spool_dir, transport.as_ref(), max_retries, inflight).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("FATAL: Spool drain aborted: {}", e);
+            eprintln!("  Error chain: {:?}", e);
+            if e.downcast_ref::<transport::AckMismatchError>().is_some() {
+                process::exit(ExitCode::IntegrityError as i32);
+            }
+            process::exit(ExitCode::FatalError as i32);
+        }
+    };
+    if drain_outcome.flushed > 0 {
+        eprintln!("INFO: Flushed {} spooled event(s)", drain_outcome.flushed);
+    }
+
+    if transport_mode == "websocket" {
+        // `run_streaming` runs indefinitely and never calls `spool::drain`
+        // again, so any entry still undelivered at this point would be
+        // stranded on disk forever. Refuse to start streaming rather than
+        // silently abandoning it.
+        if drain_outcome.remaining > 0 {
+            eprintln!(
+                "FATAL: {} spooled event(s) could not be delivered; refusing to start streaming mode \
+                 (it never retries the spool). Fix connectivity and rerun, or switch back to \
+                 RANSOMEYE_TRANSPORT=http to drain the backlog first.",
+                drain_outcome.remaining
+            );
+            process::exit(ExitCode::SpoolPending as i32);
+        }
+        run_streaming(transport.as_ref(), chain_state, &state_path).await;
+        return;
+    }
+
+    if drain_outcome.remaining > 0 {
+        // Older spooled events are still undelivered; spool the new event
+        // behind them rather than risk delivering it out of order.
+        let envelope = match construct_event_envelope(&chain_state) {
+            Ok(env) => env,
+            Err(e) => {
+                eprintln!("FATAL: Failed to construct event envelope: {}", e);
+                eprintln!("  Error chain: {:?}", e);
+                process::exit(ExitCode::StartupError as i32);
+            }
+        };
+        if let Err(e) = spool::spool(&spool_dir, &spool_config, &envelope) {
+            eprintln!("FATAL: Failed to spool event behind pending backlog: {}", e);
+            process::exit(ExitCode::FatalError as i32);
+        }
+        chain_state = chain_state.advance(envelope.integrity.hash_sha256.clone(), envelope.observed_at.clone());
+        if let Err(e) = chain::persist(&state_path, &chain_state) {
+            eprintln!("FATAL: Event was spooled but chain state could not be persisted: {}", e);
+            process::exit(ExitCode::FatalError as i32);
+        }
+        eprintln!(
+            "INFO: {} event(s) still spooled (ingest service unreachable)",
+            drain_outcome.remaining + 1
+        );
+        process::exit(ExitCode::SpoolPending as i32);
+    }
+
+    // Phase 10 requirement: Construct event envelope with proper error handling
+    let envelope = match construct_event_envelope(&chain_state) {
+        Ok(env) => env,
+        Err(e) => {
+            eprintln!("FATAL: Failed to construct event envelope: {}", e);
+            eprintln!("  Error chain: {:?}", e);
             process::exit(ExitCode::StartupError as i32);
         }
     };
 
     // Phase 10 requirement: Transmit event with proper error handling
-    match transmit_event(&client, &envelope, &ingest_url) {
-        Ok(()) => {
+    match transport::transmit_event(transport.as_ref(), &envelope).await {
+        Ok(ack) => {
+            // The chain advances and is persisted only once the event has been
+            // accepted (here) or spooled (below), so a failed POST never creates a gap.
+            chain_state = chain_state.advance(envelope.integrity.hash_sha256.clone(), ack.ingested_at.clone());
+            if let Err(e) = chain::persist(&state_path, &chain_state) {
+                eprintln!("FATAL: Event was transmitted but chain state could not be persisted: {}", e);
+                process::exit(ExitCode::FatalError as i32);
+            }
+
             eprintln!("INFO: Event transmitted successfully: {}", envelope.event_id);
+            eprintln!("INFO: Server-assigned ingested_at: {}", ack.ingested_at);
             eprintln!("SHUTDOWN: Linux Agent completed successfully");
             process::exit(ExitCode::Success as i32);
         }
         Err(e) => {
-            eprintln!("FATAL: Failed to transmit event to ingest service: {}", e);
-            eprintln!("  Error chain: {}", format!("{:?}", e));
-            eprintln!("  Event ID: {}", envelope.event_id);
+            if e.downcast_ref::<transport::AckMismatchError>().is_some() {
+                eprintln!("FATAL: Ingest acknowledgment integrity check failed: {}", e);
+                eprintln!("  Event ID: {}", envelope.event_id);
+                eprintln!("  Ingest URL: {}", ingest_url);
+                process::exit(ExitCode::IntegrityError as i32);
+            }
+
+            // Offline resilience: the chain's sequence/prev_hash assignment
+            // happens now, at spool time, not at drain time, so ordering and
+            // linkage survive the outage.
+            eprintln!("WARN: Ingest service unreachable, spooling event: {}", e);
+            if let Err(spool_err) = spool::spool(&spool_dir, &spool_config, &envelope) {
+                eprintln!("FATAL: Failed to spool undeliverable event: {}", spool_err);
+                eprintln!("  Event ID: {}", envelope.event_id);
+                process::exit(ExitCode::FatalError as i32);
+            }
+            chain_state = chain_state.advance(envelope.integrity.hash_sha256.clone(), envelope.observed_at.clone());
+            if let Err(e) = chain::persist(&state_path, &chain_state) {
+                eprintln!("FATAL: Event was spooled but chain state could not be persisted: {}", e);
+                process::exit(ExitCode::FatalError as i32);
+            }
+
+            eprintln!("INFO: Event spooled for later delivery: {}", envelope.event_id);
             eprintln!("  Ingest URL: {}", ingest_url);
-            process::exit(ExitCode::RuntimeError as i32);
+            let depth = spool::depth(&spool_dir).unwrap_or(1);
+            eprintln!("INFO: Spool depth: {}", depth);
+            process::exit(ExitCode::SpoolPending as i32);
+        }
+    }
+}
+
+/// Long-lived streaming mode for `RANSOMEYE_TRANSPORT=websocket`: construct
+/// and send up to `RANSOMEYE_INFLIGHT` chained envelopes concurrently per
+/// interval, committing each to the persisted integrity chain strictly in
+/// submission order, until the connection or an acknowledgment fails.
+async fn run_streaming(transport: &dyn transport::Transport, mut chain_state: chain::ChainState, state_path: &std::path::Path) {
+    let interval_secs: u64 = env::var("RANSOMEYE_STREAM_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    // Bounds how many envelopes may be in flight to the ingest service
+    // concurrently, same knob `spool::drain` uses.
+    let inflight: usize = env::var("RANSOMEYE_INFLIGHT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(4)
+        .max(1);
+
+    loop {
+        // Each envelope's prev_hash_sha256 is this agent's own locally
+        // computed hash, not server-assigned, so a whole batch can be
+        // chained and hand off to the transport up front instead of
+        // waiting for each acknowledgment before constructing the next.
+        // The state paired with each envelope is the chain state to persist once that specific
+        // envelope's acknowledgment actually arrives.
+        let mut batch: Vec<(EventEnvelope, chain::ChainState)> = Vec::with_capacity(inflight);
+        for _ in 0..inflight {
+            let envelope = match construct_event_envelope(&chain_state) {
+                Ok(env) => env,
+                Err(e) => {
+                    eprintln!("FATAL: Failed to construct event envelope: {}", e);
+                    eprintln!("  Error chain: {:?}", e);
+                    process::exit(ExitCode::StartupError as i32);
+                }
+            };
+            chain_state = chain_state.advance(envelope.integrity.hash_sha256.clone(), envelope.observed_at.clone());
+            batch.push((envelope, chain_state.clone()));
+        }
+
+        // Up to `inflight` sends happen concurrently, but results are
+        // applied to the persisted chain strictly in submission order
+        // (mirrors `spool::drain`): a failure is fatal here, since unlike
+        // the spool there is nowhere to leave a streamed envelope for a
+        // later run to retry.
+        let results: Vec<Result<transport::IngestAck>> = stream::iter(batch.iter().map(|(envelope, _)| envelope.clone()))
+            .map(|envelope| async move { transport::transmit_event(transport, &envelope).await })
+            .buffered(inflight)
+            .collect()
+            .await;
+
+        for ((envelope, state_after_this_envelope), result) in batch.into_iter().zip(results) {
+            match result {
+                Ok(ack) => {
+                    let mut committed = state_after_this_envelope;
+                    committed.last_ingested_at = Some(ack.ingested_at.clone());
+                    if let Err(e) = chain::persist(state_path, &committed) {
+                        eprintln!("FATAL: Event was transmitted but chain state could not be persisted: {}", e);
+                        process::exit(ExitCode::FatalError as i32);
+                    }
+                    eprintln!("INFO: Streamed event {} (sequence {})", envelope.event_id, envelope.sequence);
+                }
+                Err(e) => {
+                    if e.downcast_ref::<transport::AckMismatchError>().is_some() {
+                        eprintln!("FATAL: Ingest acknowledgment integrity check failed: {}", e);
+                        eprintln!("  Event ID: {}", envelope.event_id);
+                        process::exit(ExitCode::IntegrityError as i32);
+                    }
+
+                    eprintln!("FATAL: Streaming transmission failed: {}", e);
+                    eprintln!("  Error chain: {:?}", e);
+                    eprintln!("  Event ID: {}", envelope.event_id);
+                    process::exit(ExitCode::RuntimeError as i32);
+                }
+            }
         }
+
+        tokio::time::sleep(std::time::Duration::from_secs(interval_secs)).await;
     }
 }
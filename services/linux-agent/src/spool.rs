@@ -0,0 +1,381 @@
+// Bounded on-disk spool for event envelopes the ingest service could not
+// be reached for. Models the small bounded event cache execution clients
+// keep for blocks they could not immediately process: entries are held
+// until the next run can drain them, oldest-first, with an exponential
+// backoff between delivery attempts.
+
+use anyhow::{Context, Result};
+use futures_util::stream::{self, StreamExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::transport::{AckMismatchError, Transport};
+use crate::EventEnvelope;
+
+/// What to do when the spool is already at `max_entries` and another
+/// envelope needs to be spooled.
+pub enum OnFull {
+    EvictOldest,
+    Reject,
+}
+
+pub struct SpoolConfig {
+    pub max_entries: usize,
+    pub on_full: OnFull,
+}
+
+impl SpoolConfig {
+    pub fn from_env() -> Self {
+        let max_entries = std::env::var("RANSOMEYE_SPOOL_MAX_ENTRIES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1000);
+        let on_full = match std::env::var("RANSOMEYE_SPOOL_ON_FULL").as_deref() {
+            Ok("reject") => OnFull::Reject,
+            _ => OnFull::EvictOldest,
+        };
+        SpoolConfig { max_entries, on_full }
+    }
+}
+
+/// Default spool directory, rooted at `RANSOMEYE_SPOOL_DIR` (defaulting to
+/// `/var/lib/ransomeye/spool` if unset).
+pub fn default_spool_dir() -> PathBuf {
+    let dir = std::env::var("RANSOMEYE_SPOOL_DIR").unwrap_or_else(|_| {
+        eprintln!("INFO: RANSOMEYE_SPOOL_DIR not set, using default: /var/lib/ransomeye/spool");
+        "/var/lib/ransomeye/spool".to_string()
+    });
+    PathBuf::from(dir)
+}
+
+fn entry_path(dir: &Path, boot_id: &str, sequence: u64) -> PathBuf {
+    // Namespaced by boot_id (not just sequence), since `ChainState` resets
+    // `next_sequence` to 0 on every reboot (see `chain::load_or_init`) - two
+    // different boot segments can otherwise claim the same filename and
+    // silently overwrite each other's still-undelivered entry. The sequence
+    // is still zero-padded so that, within one boot_id's entries,
+    // lexicographic and sequence order coincide (entries from different
+    // boot_ids may interleave in either order: each boot starts its own
+    // chain segment, so cross-segment delivery order doesn't matter, only
+    // in-segment order does).
+    dir.join(format!("{}-{:020}.json", boot_id, sequence))
+}
+
+/// List spooled entries sorted by file name: grouped by boot_id, and in
+/// sequence order (oldest first) within each boot_id (see `entry_path`).
+pub fn list_sorted(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read spool directory: {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
+
+/// Number of envelopes currently held in the spool.
+pub fn depth(dir: &Path) -> Result<usize> {
+    Ok(list_sorted(dir)?.len())
+}
+
+/// Append `envelope` to the spool. `envelope.sequence`/`prev_hash_sha256`
+/// must already be assigned (spooling happens at creation time, not at
+/// drain time, so chain ordering survives the outage).
+pub fn spool(dir: &Path, config: &SpoolConfig, envelope: &EventEnvelope) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create spool directory: {}", dir.display()))?;
+
+    let entries = list_sorted(dir)?;
+    if entries.len() >= config.max_entries {
+        match config.on_full {
+            OnFull::EvictOldest => {
+                // File names only order entries within one boot_id (see
+                // `entry_path`), so the oldest entry is picked by write time,
+                // with name order breaking ties.
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|p| (fs::metadata(p).and_then(|m| m.modified()).ok(), (*p).clone()))
+                    .cloned()
+                    .expect("a full spool holds at least one entry");
+                eprintln!(
+                    "WARN: Spool full ({} entries), evicting oldest entry: {}",
+                    config.max_entries,
+                    oldest.display()
+                );
+                fs::remove_file(&oldest)
+                    .with_context(|| format!("Failed to evict oldest spool entry: {}", oldest.display()))?;
+            }
+            OnFull::Reject => {
+                anyhow::bail!(
+                    "Spool is full ({} entries) and RANSOMEYE_SPOOL_ON_FULL=reject",
+                    config.max_entries
+                );
+            }
+        }
+    }
+
+    let path = entry_path(dir, &envelope.identity.boot_id, envelope.sequence);
+    if path.exists() {
+        anyhow::bail!(
+            "Refusing to overwrite existing spool entry: {} (would silently discard a pending event)",
+            path.display()
+        );
+    }
+    let json = serde_json::to_string_pretty(envelope).context("Failed to serialize envelope for spool")?;
+    fs::write(&path, json).with_context(|| format!("Failed to write spool entry: {}", path.display()))?;
+    Ok(())
+}
+
+/// Result of a drain pass: how many entries were successfully delivered,
+/// and how many remain on disk afterwards (entries still undelivered once
+/// their retry budget ran out). Every entry is attempted in each pass.
+pub struct DrainOutcome {
+    pub flushed: usize,
+    pub remaining: usize,
+}
+
+/// Deliver a single spooled entry, retrying with exponential backoff
+/// (1s, 2s, 4s, ... capped at 30s) up to `max_retries` times after the
+/// first attempt.
+async fn deliver_one(
+    path: PathBuf,
+    transport: &dyn Transport,
+    max_retries: u32,
+) -> (PathBuf, Result<()>) {
+    let outcome = async {
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read spool entry: {}", path.display()))?;
+        let envelope: EventEnvelope = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse spool entry: {}", path.display()))?;
+
+        let mut attempt = 0u32;
+        let mut backoff = Duration::from_secs(1);
+
+        loop {
+            match crate::transport::transmit_event(transport, &envelope).await {
+                Ok(_ack) => return Ok(()),
+                Err(e) => {
+                    if e.downcast_ref::<AckMismatchError>().is_some() {
+                        // Returned as-is (not wrapped in `.context`) so the
+                        // concrete type survives for `drain`'s downcast check.
+                        return Err(e);
+                    }
+
+                    attempt += 1;
+                    if attempt > max_retries {
+                        return Err(e).with_context(|| {
+                            format!("still undelivered after {} attempts", attempt)
+                        });
+                    }
+
+                    eprintln!(
+                        "WARN: Spool entry {} attempt {} failed, retrying in {:?}: {}",
+                        path.display(),
+                        attempt,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(30));
+                }
+            }
+        }
+    }
+    .await;
+
+    (path, outcome)
+}
+
+/// Drain the spool. Up to `inflight` entries are delivered concurrently
+/// for throughput, so a later entry's delivery can genuinely complete
+/// (and be accepted by the ingest service) before an earlier entry's
+/// retries are exhausted. Because of that, every entry's outcome is
+/// committed (spool file removed, flush count advanced) strictly on its
+/// own merits - an ordinary delivery failure only leaves *that* entry
+/// spooled, it does not stop already-successful later entries from
+/// being committed, since leaving a delivered entry on disk would just
+/// resubmit it (and have it re-accepted as a duplicate) on the next
+/// drain pass. Only an `AckMismatchError` aborts the whole pass, since
+/// it signals the chain itself may no longer be trustworthy.
+pub async fn drain(dir: &Path, transport: &dyn Transport, max_retries: u32, inflight: usize) -> Result<DrainOutcome> {
+    let entries = list_sorted(dir)?;
+    let inflight = inflight.max(1);
+
+    let results: Vec<(PathBuf, Result<()>)> = stream::iter(entries)
+        .map(|path| deliver_one(path, transport, max_retries))
+        .buffered(inflight)
+        .collect()
+        .await;
+
+    let mut flushed = 0usize;
+    for (path, outcome) in results {
+        match outcome {
+            Ok(()) => {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove drained spool entry: {}", path.display()))?;
+                flushed += 1;
+            }
+            Err(e) => {
+                if e.downcast_ref::<AckMismatchError>().is_some() {
+                    return Err(e).context("Spool drain aborted: ingest acknowledgment integrity check failed");
+                }
+                eprintln!("WARN: Spool entry {} left spooled: {}", path.display(), e);
+            }
+        }
+    }
+
+    let remaining = depth(dir)?;
+    Ok(DrainOutcome { flushed, remaining })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::IngestAck;
+    use crate::{EventIdentity, EventIntegrity};
+    use async_trait::async_trait;
+    use std::collections::HashSet;
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spool-test-{}-{}", tag, std::process::id()))
+    }
+
+    fn envelope(boot_id: &str, sequence: u64) -> EventEnvelope {
+        EventEnvelope {
+            event_id: format!("event-{}-{}", boot_id, sequence),
+            machine_id: "test-machine".to_string(),
+            component: "linux_agent".to_string(),
+            component_instance_id: "test-instance".to_string(),
+            observed_at: "2026-01-01T00:00:00Z".to_string(),
+            ingested_at: "2026-01-01T00:00:00Z".to_string(),
+            sequence,
+            payload: serde_json::json!({"k": "v"}),
+            identity: EventIdentity {
+                hostname: "test-host".to_string(),
+                boot_id: boot_id.to_string(),
+                agent_version: "1.0.0".to_string(),
+            },
+            integrity: EventIntegrity {
+                hash_sha256: format!("{:0>64}", format!("{}{}", boot_id, sequence)),
+                prev_hash_sha256: None,
+            },
+        }
+    }
+
+    struct FakeTransport {
+        fail_event_ids: HashSet<String>,
+    }
+
+    #[async_trait]
+    impl Transport for FakeTransport {
+        async fn send(&self, envelope: &EventEnvelope) -> Result<IngestAck> {
+            if self.fail_event_ids.contains(&envelope.event_id) {
+                anyhow::bail!("ingest service unreachable");
+            }
+            Ok(IngestAck {
+                accepted: true,
+                ingested_at: "2026-01-01T00:00:01Z".to_string(),
+                echoed_hash_sha256: envelope.integrity.hash_sha256.clone(),
+                reason: None,
+            })
+        }
+    }
+
+    #[test]
+    fn spool_evicts_oldest_when_full() {
+        let dir = temp_dir("evict");
+        let config = SpoolConfig { max_entries: 2, on_full: OnFull::EvictOldest };
+
+        spool(&dir, &config, &envelope("boot-a", 0)).unwrap();
+        spool(&dir, &config, &envelope("boot-a", 1)).unwrap();
+        spool(&dir, &config, &envelope("boot-a", 2)).unwrap();
+
+        let entries = list_sorted(&dir).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(!entries[0].to_string_lossy().contains("boot-a-00000000000000000000"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn spool_rejects_when_full_and_configured_to_reject() {
+        let dir = temp_dir("reject");
+        let config = SpoolConfig { max_entries: 1, on_full: OnFull::Reject };
+
+        spool(&dir, &config, &envelope("boot-a", 0)).unwrap();
+        let err = spool(&dir, &config, &envelope("boot-a", 1)).unwrap_err();
+        assert!(err.to_string().contains("full"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn spool_refuses_to_overwrite_an_existing_entry() {
+        let dir = temp_dir("overwrite");
+        let config = SpoolConfig { max_entries: 10, on_full: OnFull::EvictOldest };
+
+        spool(&dir, &config, &envelope("boot-a", 0)).unwrap();
+        let err = spool(&dir, &config, &envelope("boot-a", 0)).unwrap_err();
+        assert!(err.to_string().contains("Refusing to overwrite"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn spool_namespaces_entries_by_boot_id_to_avoid_cross_boot_collision() {
+        // Two different boots both producing a sequence-0 envelope (the
+        // scenario that used to silently overwrite one with the other)
+        // must not collide.
+        let dir = temp_dir("namespace");
+        let config = SpoolConfig { max_entries: 10, on_full: OnFull::EvictOldest };
+
+        spool(&dir, &config, &envelope("boot-a", 0)).unwrap();
+        spool(&dir, &config, &envelope("boot-b", 0)).unwrap();
+
+        assert_eq!(depth(&dir).unwrap(), 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn drain_commits_later_successes_even_when_an_earlier_entry_fails() {
+        let dir = temp_dir("drain-order");
+        let config = SpoolConfig { max_entries: 10, on_full: OnFull::EvictOldest };
+
+        let failing = envelope("boot-a", 0);
+        let succeeding = envelope("boot-a", 1);
+        spool(&dir, &config, &failing).unwrap();
+        spool(&dir, &config, &succeeding).unwrap();
+
+        let transport = FakeTransport { fail_event_ids: HashSet::from([failing.event_id.clone()]) };
+        let outcome = drain(&dir, &transport, 0, 4).await.unwrap();
+
+        assert_eq!(outcome.flushed, 1);
+        assert_eq!(outcome.remaining, 1);
+        let remaining = list_sorted(&dir).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(remaining[0].to_string_lossy().contains(&format!("{:020}", 0)));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn drain_removes_all_entries_on_full_success() {
+        let dir = temp_dir("drain-all");
+        let config = SpoolConfig { max_entries: 10, on_full: OnFull::EvictOldest };
+
+        spool(&dir, &config, &envelope("boot-a", 0)).unwrap();
+        spool(&dir, &config, &envelope("boot-a", 1)).unwrap();
+
+        let transport = FakeTransport { fail_event_ids: HashSet::new() };
+        let outcome = drain(&dir, &transport, 0, 4).await.unwrap();
+
+        assert_eq!(outcome.flushed, 2);
+        assert_eq!(outcome.remaining, 0);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,357 @@
+// Transport abstraction for submitting event envelopes to the ingest
+// service. `transmit_event` owns the behavior that must be identical no
+// matter which wire protocol is in play - ack validation, logging, and
+// exit-code-relevant error classification - while each `Transport` impl
+// only owns the raw send/receive exchange for its protocol.
+//
+// Both impls are async, driven by the tokio runtime entered from `main`,
+// so multiple envelopes can be in flight concurrently (see `spool::drain`
+// and, for the streaming transport, `main::run_streaming`).
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::canonical;
+use crate::EventEnvelope;
+
+/// Structured acknowledgment returned by the ingest service for a submitted
+/// event envelope. Borrows the consensus valid/invalid confirmation pattern
+/// from execution-layer clients: the caller does not trust a bare 2xx (or,
+/// for the streaming transport, a bare received frame), it checks what the
+/// server actually says it did with the payload.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IngestAck {
+    #[serde(rename = "accepted")]
+    pub accepted: bool,
+
+    #[serde(rename = "ingested_at")]
+    pub ingested_at: String,
+
+    #[serde(rename = "echoed_hash_sha256")]
+    pub echoed_hash_sha256: String,
+
+    #[serde(rename = "reason")]
+    pub reason: Option<String>,
+}
+
+/// Marker error for a detected ingest acknowledgment mismatch, so `main`
+/// can report it via the dedicated `ExitCode::IntegrityError` path instead
+/// of the path used for ordinary transport failures (spooling the event,
+/// or a runtime error in streaming mode).
+#[derive(Debug)]
+pub struct AckMismatchError(pub String);
+
+impl std::fmt::Display for AckMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AckMismatchError {}
+
+/// A wire protocol capable of delivering one envelope to the ingest
+/// service and returning its raw (not yet validated) acknowledgment.
+/// Selected at startup via `RANSOMEYE_TRANSPORT` (`http` default,
+/// `websocket` for the long-lived streaming alternative). `send` is async
+/// so multiple envelopes can be in flight concurrently under a bounded
+/// `RANSOMEYE_INFLIGHT`.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, envelope: &EventEnvelope) -> Result<IngestAck>;
+}
+
+/// One-shot HTTP POST transport (the default, matching the agent's
+/// original Phase 4/10 behavior), now backed by an async `reqwest::Client`.
+pub struct HttpTransport {
+    client: Client,
+    ingest_url: String,
+}
+
+impl HttpTransport {
+    pub fn new(ingest_url: String) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(HttpTransport { client, ingest_url })
+    }
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn send(&self, envelope: &EventEnvelope) -> Result<IngestAck> {
+        eprintln!("INFO: Transmitting event to ingest service: {}", self.ingest_url);
+
+        let response = match self.client.post(&self.ingest_url).json(envelope).send().await {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("ERROR: Failed to send HTTP request to ingest service: {}", e);
+                eprintln!("  Ingest URL: {}", self.ingest_url);
+                eprintln!("  Event ID: {}", envelope.event_id);
+                anyhow::bail!("HTTP request failed: {}", e);
+            }
+        };
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_else(|_| String::from("(no response body)"));
+            eprintln!("ERROR: Ingest service returned error status: {}", status);
+            eprintln!("  Response body: {}", body);
+            eprintln!("  Event ID: {}", envelope.event_id);
+            anyhow::bail!("Ingest service returned error status {}: {}", status, body);
+        }
+
+        response.json::<IngestAck>().await.with_context(|| {
+            format!(
+                "Failed to parse ingest acknowledgment for event {}",
+                envelope.event_id
+            )
+        })
+    }
+}
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Long-lived WebSocket transport: one connection carries a continuous
+/// sequence of canonical-JSON envelope frames, each chained via the
+/// persisted `prev_hash_sha256`, reading back one acknowledgment frame
+/// per event so backpressure is observable.
+///
+/// The connection is split into its write and read halves so multiple
+/// `send` calls can have frames in flight concurrently (bounded by
+/// `RANSOMEYE_INFLIGHT`, see `main::run_streaming`): a dedicated reader
+/// task demultiplexes acknowledgments as they arrive and hands each one
+/// to the oldest outstanding `send` call, since the protocol carries no
+/// correlation id and relies on WebSocket's in-order delivery instead.
+pub struct WebSocketTransport {
+    writer: Mutex<SplitSink<WsStream, Message>>,
+    /// Waiters for outstanding sends, oldest (next acknowledgment due)
+    /// first. A `send` call is only ever queued here in the same critical
+    /// section as the frame write that produced it, so queue order always
+    /// matches wire order.
+    pending: Arc<Mutex<VecDeque<oneshot::Sender<Result<IngestAck>>>>>,
+}
+
+impl WebSocketTransport {
+    pub async fn connect(ingest_url: &str) -> Result<Self> {
+        eprintln!("INFO: Opening WebSocket connection to ingest service: {}", ingest_url);
+        let (socket, _response) = tokio_tungstenite::connect_async(ingest_url)
+            .await
+            .with_context(|| format!("Failed to open WebSocket connection to {}", ingest_url))?;
+
+        let (writer, reader) = socket.split();
+        let pending = Arc::new(Mutex::new(VecDeque::new()));
+        tokio::spawn(read_acknowledgments(reader, pending.clone()));
+
+        Ok(WebSocketTransport {
+            writer: Mutex::new(writer),
+            pending,
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    async fn send(&self, envelope: &EventEnvelope) -> Result<IngestAck> {
+        let frame = canonical::to_canonical_json(envelope)
+            .context("Failed to canonicalize envelope for WebSocket frame")?;
+
+        let (tx, rx) = oneshot::channel();
+        {
+            // Held across the write (but not the ack wait below) so the
+            // queue order this send is registered under always matches
+            // the order its frame actually hits the wire, even when
+            // called concurrently from several in-flight sends.
+            let mut pending = self.pending.lock().await;
+            let mut writer = self.writer.lock().await;
+            writer
+                .send(Message::Text(frame))
+                .await
+                .with_context(|| format!("Failed to send WebSocket frame for event {}", envelope.event_id))?;
+            pending.push_back(tx);
+        }
+
+        rx.await
+            .context("WebSocket acknowledgment reader task ended before replying")?
+            .with_context(|| format!("Failed to receive WebSocket acknowledgment for event {}", envelope.event_id))
+    }
+}
+
+/// Background task owning the WebSocket's read half for the lifetime of
+/// the connection: reads one frame at a time and resolves the oldest
+/// outstanding `send` call's waiter with it, so several `send` calls can
+/// have frames in flight without each one blocking on its own read.
+async fn read_acknowledgments(
+    mut reader: SplitStream<WsStream>,
+    pending: Arc<Mutex<VecDeque<oneshot::Sender<Result<IngestAck>>>>>,
+) {
+    loop {
+        let message = match reader.next().await {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                fail_all_pending(&pending, format!("Failed to read WebSocket acknowledgment: {}", e)).await;
+                return;
+            }
+            None => {
+                fail_all_pending(&pending, "WebSocket connection closed while awaiting acknowledgment".to_string()).await;
+                return;
+            }
+        };
+
+        let ack_result: Result<IngestAck> = match message {
+            Message::Text(text) => serde_json::from_str::<IngestAck>(&text).context("Failed to parse WebSocket acknowledgment"),
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Close(frame) => {
+                fail_all_pending(&pending, format!("WebSocket connection closed by ingest service: {:?}", frame)).await;
+                return;
+            }
+            other => Err(anyhow::anyhow!("Unexpected WebSocket message type: {:?}", other)),
+        };
+
+        if let Some(waiter) = pending.lock().await.pop_front() {
+            // The `send` call may have already given up (e.g. its future
+            // was dropped while awaiting the acknowledgment), in which case
+            // nobody is listening on the other end; that's fine, drop the result.
+            let _ = waiter.send(ack_result);
+        }
+    }
+}
+
+/// Fail every outstanding waiter once the connection is no longer usable,
+/// so no `send` call is left awaiting an acknowledgment that will never
+/// arrive.
+async fn fail_all_pending(pending: &Mutex<VecDeque<oneshot::Sender<Result<IngestAck>>>>, reason: String) {
+    let mut queue = pending.lock().await;
+    while let Some(waiter) = queue.pop_front() {
+        let _ = waiter.send(Err(anyhow::anyhow!("{}", reason)));
+    }
+}
+
+/// Shared transmission logic: delegates the raw exchange to `transport`,
+/// then validates the acknowledgment the same way regardless of which
+/// wire protocol produced it.
+pub async fn transmit_event(transport: &dyn Transport, envelope: &EventEnvelope) -> Result<IngestAck> {
+    let ack = transport.send(envelope).await?;
+
+    if !ack.accepted {
+        let reason = ack.reason.clone().unwrap_or_else(|| "(no reason given)".to_string());
+        eprintln!("ERROR: Ingest service declined event: {}", reason);
+        eprintln!("  Event ID: {}", envelope.event_id);
+        anyhow::bail!("Ingest service declined event {}: {}", envelope.event_id, reason);
+    }
+
+    if ack.echoed_hash_sha256 != envelope.integrity.hash_sha256 {
+        eprintln!(
+            "ERROR: Ingest acknowledgment echoed a different hash than was sent (possible corrupting proxy)"
+        );
+        eprintln!("  Sent hash:    {}", envelope.integrity.hash_sha256);
+        eprintln!("  Echoed hash:  {}", ack.echoed_hash_sha256);
+        eprintln!("  Event ID: {}", envelope.event_id);
+        return Err(AckMismatchError(format!(
+            "ingest acknowledgment echoed hash {} but event {} was sent with hash {}",
+            ack.echoed_hash_sha256, envelope.event_id, envelope.integrity.hash_sha256
+        ))
+        .into());
+    }
+
+    eprintln!("INFO: Event transmission successful: {}", envelope.event_id);
+    Ok(ack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventIdentity, EventIntegrity};
+
+    struct FakeTransport {
+        ack: IngestAck,
+    }
+
+    #[async_trait]
+    impl Transport for FakeTransport {
+        async fn send(&self, _envelope: &EventEnvelope) -> Result<IngestAck> {
+            Ok(self.ack.clone())
+        }
+    }
+
+    fn envelope(hash_sha256: &str) -> EventEnvelope {
+        EventEnvelope {
+            event_id: "event-0".to_string(),
+            machine_id: "test-machine".to_string(),
+            component: "linux_agent".to_string(),
+            component_instance_id: "test-instance".to_string(),
+            observed_at: "2026-01-01T00:00:00Z".to_string(),
+            ingested_at: "2026-01-01T00:00:00Z".to_string(),
+            sequence: 0,
+            payload: serde_json::json!({"k": "v"}),
+            identity: EventIdentity {
+                hostname: "test-host".to_string(),
+                boot_id: "11111111-1111-1111-1111-111111111111".to_string(),
+                agent_version: "1.0.0".to_string(),
+            },
+            integrity: EventIntegrity {
+                hash_sha256: hash_sha256.to_string(),
+                prev_hash_sha256: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn transmit_event_accepts_matching_ack() {
+        let transport = FakeTransport {
+            ack: IngestAck {
+                accepted: true,
+                ingested_at: "2026-01-01T00:00:01Z".to_string(),
+                echoed_hash_sha256: "a".repeat(64),
+                reason: None,
+            },
+        };
+        let envelope = envelope(&"a".repeat(64));
+
+        let ack = transmit_event(&transport, &envelope).await.unwrap();
+        assert!(ack.accepted);
+    }
+
+    #[tokio::test]
+    async fn transmit_event_rejects_declined_ack() {
+        let transport = FakeTransport {
+            ack: IngestAck {
+                accepted: false,
+                ingested_at: "2026-01-01T00:00:01Z".to_string(),
+                echoed_hash_sha256: "a".repeat(64),
+                reason: Some("payload too large".to_string()),
+            },
+        };
+        let envelope = envelope(&"a".repeat(64));
+
+        let err = transmit_event(&transport, &envelope).await.unwrap_err();
+        assert!(err.to_string().contains("payload too large"));
+        assert!(err.downcast_ref::<AckMismatchError>().is_none());
+    }
+
+    #[tokio::test]
+    async fn transmit_event_flags_echoed_hash_mismatch_as_ack_mismatch_error() {
+        let transport = FakeTransport {
+            ack: IngestAck {
+                accepted: true,
+                ingested_at: "2026-01-01T00:00:01Z".to_string(),
+                echoed_hash_sha256: "b".repeat(64),
+                reason: None,
+            },
+        };
+        let envelope = envelope(&"a".repeat(64));
+
+        let err = transmit_event(&transport, &envelope).await.unwrap_err();
+        assert!(err.downcast_ref::<AckMismatchError>().is_some());
+    }
+}